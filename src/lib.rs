@@ -14,6 +14,10 @@ extern crate collections;
 extern crate core;
 extern crate iron;
 extern crate hyper;
+extern crate cookie;
+extern crate serialize;
+extern crate "rust-crypto" as crypto;
+extern crate time;
 #[cfg(test)]
 extern crate iron_test as test;
 
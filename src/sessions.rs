@@ -6,12 +6,23 @@
 //! Key-generating functions and custom stores can be used
 //! to customize functionality.
 
+use std::sync::Arc;
 use iron::{Request, Response, IronResult};
 use iron::middleware::BeforeMiddleware;
 use hyper::status::StatusCode;
 use super::sessionstore::SessionStore;
 use std::marker::{PhantomData, Reflect};
 
+/// Produces the key used to select a session for a `Request`.
+///
+/// Returning `Ok(None)` suppresses guest sessioning: no `Session` is
+/// inserted into the `alloy`, so downstream middleware sees none at all.
+/// Returning `Err` short-circuits the chain with the given status,
+/// without ever calling downstream middleware. This is what makes keys
+/// that must be parsed and verified -- a signed cookie, say -- a
+/// legitimate, fallible source of keys rather than a bare function.
+type KeyGenerator<K> = Box<Fn(&Request) -> IronResult<Option<K>> + Send + Sync>;
+
 /// The sessioning middleware.
 ///
 /// `Sessions` middleware is given a key-generating function and a
@@ -22,14 +33,17 @@ use std::marker::{PhantomData, Reflect};
 /// middleware to create/swap/edit sessions stored to a key.
 ///
 /// `Sessions` allows guest sessioning (sessions without explicit authorization).
-/// To prevent guest sessioning, the key generator can produce
-/// an `Option` value so that all unauthorized users have an empty session.
+/// To prevent guest sessioning, the key generator can return `Ok(None)`
+/// so that all unauthorized users have no session at all, rather than an
+/// empty one. A key generator that needs to parse and verify something
+/// fallible -- a signed cookie, for example -- can also return `Err` to
+/// short-circuit the chain with a given status.
 ///
 /// Session keys can be stored in the `Request` or `Alloy`.
 /// Usually, keys are stored in signed cookies, but anything
 /// retrievable from `Request` or `Alloy` will work.
 pub struct Sessions<K, V, S> {
-    key_generator: fn(&Request) -> K,
+    key_generator: Arc<KeyGenerator<K>>,
     value_type: PhantomData<V>,
     session_store: S
 }
@@ -37,7 +51,7 @@ pub struct Sessions<K, V, S> {
 impl<K, V, S: SessionStore<K, V> + Clone> Clone for Sessions<K, V, S> {
     fn clone(&self) -> Sessions<K, V, S> {
         Sessions {
-            key_generator: self.key_generator,
+            key_generator: self.key_generator.clone(),
             session_store: self.session_store.clone(),
             value_type: PhantomData
         }
@@ -50,16 +64,18 @@ impl<K, V, S: SessionStore<K, V>> Sessions<K, V, S> {
     ///
     /// `key_generator` should generate keys based on the `Request` and `Alloy`.
     /// These keys should be unique, as identical keys will map to the same session.
+    /// It may capture its own state (a signing secret, say), and may fail:
+    /// see `KeyGenerator` for what `Ok(None)` and `Err` mean.
     ///
     /// The `Alloy` can be used to access
     /// stores such as cookies to allow persistent sessions for users.
     ///
     /// `session_store` must implement the `SessionStore` trait.
     /// A default `Session` is provided to fulfill this.
-    pub fn new(key_generator: fn(&Request) -> K,
-               store: S) -> Sessions<K, V, S> {
+    pub fn new<F>(key_generator: F, store: S) -> Sessions<K, V, S>
+      where F: Fn(&Request) -> IronResult<Option<K>> + Send + Sync + 'static {
         Sessions {
-            key_generator: key_generator,
+            key_generator: Arc::new(box key_generator),
             session_store: store,
             value_type: PhantomData
         }
@@ -68,13 +84,20 @@ impl<K, V, S: SessionStore<K, V>> Sessions<K, V, S> {
 
 impl<K: 'static, V, S: SessionStore<K, V> + Clone> BeforeMiddleware for Sessions<K, V, S> {
     /// Adds the session store to the `alloy`.
+    /// Nothing is added when the key generator returns `Ok(None)`.
 
     fn before(&self, req: &mut Request) -> IronResult<()> {
-        // Retrieve the session for this request
-        let session = self.session_store.select_session((self.key_generator)(req));
-
-        // Store this session in the alloy
-        req.alloy.insert(session);
+        match (self.key_generator)(req) {
+            // Retrieve the session for this request and store it in the alloy
+            Ok(Some(key)) => {
+                let session = self.session_store.select_session(key);
+                req.alloy.insert(session);
+            },
+            // No key for this request: leave no Session in the alloy at all
+            Ok(None) => (),
+            // The key generator couldn't produce a key: short-circuit the chain
+            Err(err) => return Err(err)
+        }
         Ok(())
     }
 }
@@ -86,10 +109,30 @@ mod test {
     pub use super::super::sessionstore::session::*;
     pub use super::super::sessionstore::hashsession::*;
     pub use iron::*;
+    pub use iron::error::IronError;
     pub use test::mock::{request, response};
     pub use std::sync::{Arc, Mutex};
+    pub use std::fmt;
+    pub use std::error::Error;
+
+    #[derive(Show)]
+    struct NoKeyError;
+
+    impl fmt::Display for NoKeyError {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "no key for this request")
+        }
+    }
+
+    impl Error for NoKeyError {
+        fn description(&self) -> &str { "no key for this request" }
+    }
 
-    pub fn get_session_id(_: &Request) -> char {'a'}
+    pub fn get_session_id(_: &Request) -> IronResult<Option<char>> { Ok(Some('a')) }
+    pub fn no_session_id(_: &Request) -> IronResult<Option<char>> { Ok(None) }
+    pub fn reject_session_id(_: &Request) -> IronResult<Option<char>> {
+        Err(IronError::new(NoKeyError, StatusCode::Forbidden))
+    }
 
     pub fn check_session_char_char(req: &mut Request, _: &mut Response) -> Status {
         let _ = req.alloy.find::<Session<char, char>>().unwrap();
@@ -99,6 +142,10 @@ mod test {
         let _ = req.alloy.find::<Session<char, u32>>().unwrap();
         Continue
     }
+    pub fn check_no_session(req: &mut Request, _: &mut Response) -> Status {
+        assert!(req.alloy.find::<Session<char, char>>().is_none());
+        Continue
+    }
 
     mod enter {
         use super::*;
@@ -114,5 +161,26 @@ mod test {
               &mut request::new(::http::method::Get, "localhost:3000"),
               &mut response::new());
         }
+
+        #[test]
+        fn skips_session_when_key_generator_returns_none() {
+            let mut test_server: Server = Iron::new();
+            test_server.chain.link(Sessions::new(no_session_id, HashSessionStore::<char, char>::new()));
+            test_server.chain.link(FromFn::new(check_no_session));
+            let _ = test_server.chain.dispatch(
+              &mut request::new(::http::method::Get, "localhost:3000"),
+              &mut response::new());
+        }
+
+        #[test]
+        fn short_circuits_when_key_generator_errors() {
+            let mut test_server: Server = Iron::new();
+            test_server.chain.link(Sessions::new(reject_session_id, HashSessionStore::<char, char>::new()));
+            test_server.chain.link(FromFn::new(check_session_char_char));
+            let res = test_server.chain.dispatch(
+              &mut request::new(::http::method::Get, "localhost:3000"),
+              &mut response::new());
+            assert!(res.is_err());
+        }
     }
 }
@@ -0,0 +1,385 @@
+use std::cell::RefCell;
+use std::marker::PhantomData;
+use std::str;
+use serialize::{json, Encodable, Decodable};
+use serialize::base64::{mod, ToBase64, FromBase64};
+use crypto::hmac::Hmac;
+use crypto::sha2::Sha256;
+use crypto::mac::{Mac, MacResult};
+use iron::{Request, Response, IronResult};
+use iron::middleware::{BeforeMiddleware, AfterMiddleware};
+use cookie::{Cookie as CookieHeader, CookiePair};
+use iron::headers::SetCookie;
+use super::SessionStore;
+
+/// HMAC-SHA256 is used to authenticate the serialized session payload.
+const MAC_LEN: uint = 32;
+
+/// The `SameSite` attribute written on the outgoing cookie.
+#[deriving(Clone)]
+pub enum SameSite {
+    Strict,
+    Lax,
+    None
+}
+
+/// A `SessionStore` that keeps no server-side state at all.
+///
+/// Instead of looking values up in a shared map, `CookieSessionStore`
+/// serializes the entire session value into the named cookie on the way
+/// out, and reads it back off the `Request` on the way in. The cookie is
+/// authenticated with `HMAC-SHA256` so clients cannot forge or tamper
+/// with the contents; it is not encrypted, so do not store secrets in
+/// `V` (see `EncryptedSessionStore` to add confidentiality underneath
+/// another backend).
+///
+/// Because there is no shared map, `insert`/`find`/`swap`/`upsert` all
+/// operate on a per-request holding cell rather than an `Arc<RwLock<_>>`.
+/// Iron dispatches a single request to completion on one thread, so the
+/// cell is a plain `thread_local!`, not a field shared by `Arc` across
+/// every clone of the store -- two requests handled concurrently on
+/// different threads never see each other's session. `CookieSessionStore`
+/// is itself a `BeforeMiddleware` that decodes the incoming cookie into
+/// the cell; downstream middleware (including `Sessions`) then reads and
+/// mutates it through the usual `Session` handle, and the paired
+/// `AfterMiddleware` impl re-signs whatever is left in the cell and
+/// writes it back as a `Set-Cookie`.
+///
+/// #### To use:
+/// ```ignore
+/// let store = CookieSessionStore::<MySession>::new("my_app_session", secret);
+/// server.link_before(store.clone());
+/// server.link(Sessions::new(key_gen_fn, store.clone()));
+/// server.link_after(store);
+/// ```
+pub struct CookieSessionStore<V> {
+    cookie_name: String,
+    secret: [u8, ..32],
+    path: String,
+    http_only: bool,
+    secure: bool,
+    same_site: SameSite,
+    value_type: PhantomData<V>
+}
+
+impl<V: Send + Clone> Clone for CookieSessionStore<V> {
+    fn clone(&self) -> CookieSessionStore<V> {
+        CookieSessionStore {
+            cookie_name: self.cookie_name.clone(),
+            secret: self.secret,
+            path: self.path.clone(),
+            http_only: self.http_only,
+            secure: self.secure,
+            same_site: self.same_site.clone(),
+            value_type: PhantomData
+        }
+    }
+}
+
+impl<V: Send + Encodable<json::Encoder<'static>, io::IoError> + Decodable<json::Decoder, json::DecoderError>> CookieSessionStore<V> {
+    /// Create a new cookie-backed session store.
+    ///
+    /// `cookie_name` is the name of the cookie the session is stored
+    /// under; `secret` is the 32-byte HMAC key used to authenticate it.
+    /// Defaults to `Path=/`, `HttpOnly`, not `Secure`, and `SameSite=Lax`;
+    /// use the `path`/`http_only`/`secure`/`same_site` builder methods to
+    /// change these.
+    pub fn new(cookie_name: &str, secret: [u8, ..32]) -> CookieSessionStore<V> {
+        CookieSessionStore {
+            cookie_name: cookie_name.to_string(),
+            secret: secret,
+            path: "/".to_string(),
+            http_only: true,
+            secure: false,
+            same_site: SameSite::Lax,
+            value_type: PhantomData
+        }
+    }
+
+    /// Serve the cookie only under the given `path`.
+    pub fn path(mut self, path: &str) -> CookieSessionStore<V> {
+        self.path = path.to_string();
+        self
+    }
+
+    /// Mark the cookie `HttpOnly` (default) or not.
+    pub fn http_only(mut self, http_only: bool) -> CookieSessionStore<V> {
+        self.http_only = http_only;
+        self
+    }
+
+    /// Mark the cookie `Secure`, so it is only ever sent over HTTPS.
+    pub fn secure(mut self, secure: bool) -> CookieSessionStore<V> {
+        self.secure = secure;
+        self
+    }
+
+    /// Set the `SameSite` attribute written on the cookie.
+    pub fn same_site(mut self, same_site: SameSite) -> CookieSessionStore<V> {
+        self.same_site = same_site;
+        self
+    }
+
+    /// Read and verify the named cookie off `req`, populating the
+    /// thread-local holding cell with the decoded value, if any. Called
+    /// by this store's own `BeforeMiddleware` impl, ahead of everything
+    /// downstream that reads the cell.
+    ///
+    /// Any failure to decode, authenticate, or deserialize the cookie is
+    /// treated as an absent session rather than an error: malformed
+    /// input from a client must never cause a panic or a hard failure.
+    pub fn read_from(&self, req: &Request) {
+        let value = req.headers.get::<CookieHeader>()
+            .and_then(|header| header.iter().find(|c| c.name.as_slice() == self.cookie_name.as_slice()))
+            .and_then(|c| self.verify_and_decode(c.value.as_slice()));
+        with_cell(|cell| *cell = value);
+    }
+
+    fn verify_and_decode(&self, encoded: &str) -> Option<V> {
+        let raw = match encoded.as_bytes().from_base64() {
+            Ok(raw) => raw,
+            Err(_) => return None
+        };
+        if raw.len() < MAC_LEN {
+            return None;
+        }
+        let (payload, mac) = raw.split_at(raw.len() - MAC_LEN);
+        if !self.mac_for(payload).eq(&MacResult::new(mac)) {
+            return None;
+        }
+        match str::from_utf8(payload) {
+            Some(payload) => json::decode(payload).ok(),
+            None => None
+        }
+    }
+
+    fn encode_and_sign(&self, value: &V) -> String {
+        let mut payload = json::encode(value).into_bytes();
+        payload.push_all(self.mac_for(payload.as_slice()).code());
+        payload.as_slice().to_base64(base64::STANDARD)
+    }
+
+    fn mac_for(&self, payload: &[u8]) -> MacResult {
+        let mut hmac = Hmac::new(Sha256::new(), &self.secret);
+        hmac.input(payload);
+        hmac.result()
+    }
+}
+
+/// Run `f` against the calling thread's holding cell. Declared inside a
+/// function so the generic parameter `V` is monomorphized along with it:
+/// each `V` this store is instantiated with gets its own thread-local
+/// storage, scoped to the current request's handling thread.
+fn with_cell<V, R, F: FnOnce(&mut Option<V>) -> R>(f: F) -> R {
+    thread_local!(static CELL: RefCell<Option<V>> = RefCell::new(None));
+    CELL.with(|cell| f(&mut *cell.borrow_mut()))
+}
+
+impl<V: Send> BeforeMiddleware for CookieSessionStore<V>
+  where V: Encodable<json::Encoder<'static>, io::IoError> + Decodable<json::Decoder, json::DecoderError> {
+    /// Decode and verify the incoming cookie into the thread-local cell
+    /// before the rest of the chain runs.
+    fn before(&self, req: &mut Request) -> IronResult<()> {
+        self.read_from(req);
+        Ok(())
+    }
+}
+
+impl<K, V: Send + Clone> SessionStore<K, V> for CookieSessionStore<V> {
+    fn insert(&self, _key: &K, val: V) {
+        with_cell(|cell| {
+            if cell.is_none() {
+                *cell = Some(val);
+            }
+        })
+    }
+    fn find(&self, _key: &K) -> Option<V> {
+        with_cell(|cell: &mut Option<V>| cell.clone())
+    }
+    fn swap(&self, _key: &K, value: V) -> Option<V> {
+        with_cell(|cell| {
+            let old = cell.take();
+            *cell = Some(value);
+            old
+        })
+    }
+    fn upsert<F>(&self, _key: &K, value: V, mutator: F) -> V
+      where F: Fn(&mut V) -> () {
+        with_cell(|cell| {
+            match *cell {
+                Some(ref mut v) => {
+                    mutator(v);
+                    return v.clone()
+                },
+                None => ()
+            }
+            *cell = Some(value.clone());
+            value
+        })
+    }
+    fn remove(&self, _key: &K) -> bool {
+        with_cell(|cell: &mut Option<V>| cell.take().is_some())
+    }
+}
+
+impl SameSite {
+    fn as_attribute(&self) -> &'static str {
+        match *self {
+            SameSite::Strict => "; SameSite=Strict",
+            SameSite::Lax => "; SameSite=Lax",
+            SameSite::None => "; SameSite=None"
+        }
+    }
+}
+
+impl<V: Send + Encodable<json::Encoder<'static>, io::IoError>> AfterMiddleware for CookieSessionStore<V> {
+    /// Re-serialize whatever value is left in the holding cell, sign it,
+    /// and write it back as a `Set-Cookie` on the response.
+    fn after(&self, _req: &mut Request, mut res: Response) -> IronResult<Response> {
+        let encoded = with_cell(|cell: &mut Option<V>| cell.as_ref().map(|value| self.encode_and_sign(value)));
+        if let Some(encoded) = encoded {
+            let mut cookie = CookiePair::new(self.cookie_name.clone(), encoded);
+            cookie.path = Some(self.path.clone());
+            cookie.http_only = self.http_only;
+            cookie.secure = self.secure;
+
+            // `CookiePair` has no field for `SameSite`, so append it onto
+            // the serialized header value by hand rather than silently
+            // dropping the attribute the store advertises.
+            let mut header_value = cookie.to_string();
+            header_value.push_str(self.same_site.as_attribute());
+            res.headers.set_raw("Set-Cookie", vec![header_value.into_bytes()]);
+        }
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    pub use super::*;
+    pub use iron::{Request, Response};
+    pub use iron::middleware::{BeforeMiddleware, AfterMiddleware};
+    pub use cookie::{Cookie as CookieHeader, CookiePair};
+    pub use test::mock::{request, response};
+    pub use super::super::SessionStore;
+
+    const SECRET: [u8, ..32] = [7u8, ..32];
+    const OTHER_SECRET: [u8, ..32] = [9u8, ..32];
+
+    fn store() -> CookieSessionStore<String> {
+        CookieSessionStore::<String>::new("session", SECRET)
+    }
+
+    fn request_with_cookie(name: &str, value: String) -> Request {
+        let mut req = request::new(::http::method::Get, "localhost:3000");
+        let cookie = CookiePair::new(name.to_string(), value);
+        req.headers.set(CookieHeader(vec![cookie]));
+        req
+    }
+
+    #[test]
+    fn before_populates_the_cell_from_a_valid_cookie() {
+        let store = store();
+        let signed = store.encode_and_sign(&"hello".to_string());
+        let mut req = request_with_cookie("session", signed);
+
+        BeforeMiddleware::before(&store, &mut req).unwrap();
+        assert_eq!(SessionStore::find(&store, &()), Some("hello".to_string()));
+    }
+
+    #[test]
+    fn after_round_trips_through_before() {
+        let store = store();
+        SessionStore::insert(&store, &(), "round trip".to_string());
+
+        let mut res = response::new();
+        res = AfterMiddleware::after(&store, &mut request::new(::http::method::Get, "localhost:3000"), res).unwrap();
+        let cookie = res.headers.get::<super::SetCookie>().unwrap().0[0].clone();
+
+        let store2 = store();
+        let mut req = request_with_cookie("session", cookie.value);
+        BeforeMiddleware::before(&store2, &mut req).unwrap();
+        assert_eq!(SessionStore::find(&store2, &()), Some("round trip".to_string()));
+    }
+
+    fn assert_same_site_attribute(same_site: SameSite, attribute: &str) {
+        let store = store().same_site(same_site);
+        SessionStore::insert(&store, &(), "hello".to_string());
+
+        let mut res = response::new();
+        res = AfterMiddleware::after(&store, &mut request::new(::http::method::Get, "localhost:3000"), res).unwrap();
+        let raw = res.headers.get_raw("Set-Cookie").unwrap();
+        let header_value = String::from_utf8_lossy(raw[0].as_slice()).into_owned();
+        assert!(header_value.as_slice().contains(attribute),
+                "expected {} in Set-Cookie header {}", attribute, header_value);
+    }
+
+    #[test]
+    fn after_sets_same_site_strict() {
+        assert_same_site_attribute(SameSite::Strict, "; SameSite=Strict");
+    }
+
+    #[test]
+    fn after_sets_same_site_lax() {
+        assert_same_site_attribute(SameSite::Lax, "; SameSite=Lax");
+    }
+
+    #[test]
+    fn after_sets_same_site_none() {
+        assert_same_site_attribute(SameSite::None, "; SameSite=None");
+    }
+
+    #[test]
+    fn rejects_a_cookie_shorter_than_the_mac() {
+        let store = store();
+        let too_short = [0u8, ..10].as_slice().to_base64(super::base64::STANDARD);
+        let mut req = request_with_cookie("session", too_short);
+        BeforeMiddleware::before(&store, &mut req).unwrap();
+        assert_eq!(SessionStore::find(&store, &()), None);
+    }
+
+    #[test]
+    fn rejects_a_cookie_with_a_flipped_mac_byte() {
+        let store = store();
+        let mut raw = store.encode_and_sign(&"hello".to_string()).as_bytes().from_base64().unwrap();
+        let last = raw.len() - 1;
+        raw[last] ^= 0xff;
+        let tampered = raw.as_slice().to_base64(super::base64::STANDARD);
+        let mut req = request_with_cookie("session", tampered);
+        BeforeMiddleware::before(&store, &mut req).unwrap();
+        assert_eq!(SessionStore::find(&store, &()), None);
+    }
+
+    #[test]
+    fn rejects_a_cookie_signed_under_a_different_secret() {
+        let signer = CookieSessionStore::<String>::new("session", OTHER_SECRET);
+        let signed = signer.encode_and_sign(&"hello".to_string());
+
+        let store = store();
+        let mut req = request_with_cookie("session", signed);
+        BeforeMiddleware::before(&store, &mut req).unwrap();
+        assert_eq!(SessionStore::find(&store, &()), None);
+    }
+
+    #[test]
+    fn rejects_malformed_base64_without_panicking() {
+        let store = store();
+        let mut req = request_with_cookie("session", "not valid base64!!".to_string());
+        BeforeMiddleware::before(&store, &mut req).unwrap();
+        assert_eq!(SessionStore::find(&store, &()), None);
+    }
+
+    #[test]
+    fn rejects_a_payload_that_fails_to_deserialize() {
+        let store = store();
+        // Authentic MAC over a payload that isn't valid JSON for `String`.
+        let payload = b"not json".to_vec();
+        let mut raw = payload.clone();
+        raw.push_all(store.mac_for(payload.as_slice()).code());
+        let encoded = raw.as_slice().to_base64(super::base64::STANDARD);
+
+        let mut req = request_with_cookie("session", encoded);
+        BeforeMiddleware::before(&store, &mut req).unwrap();
+        assert_eq!(SessionStore::find(&store, &()), None);
+    }
+}
@@ -0,0 +1,223 @@
+use std::marker::PhantomData;
+use std::{io, str};
+use std::rand::{Rng, OsRng};
+use serialize::json;
+use crypto::chacha20poly1305::ChaCha20Poly1305;
+use crypto::aead::{AeadEncryptor, AeadDecryptor};
+use super::SessionStore;
+
+const KEY_LEN: uint = 32;
+const NONCE_LEN: uint = 12;
+const TAG_LEN: uint = 16;
+
+/// A `SessionStore` decorator that encrypts values at rest.
+///
+/// Wraps an inner `SessionStore<K, Vec<u8>>` and transparently encrypts
+/// `V` on the way in and decrypts it on the way out, so a backend that
+/// persists or shares its memory (a database-backed store, a shared
+/// cache) never sees plaintext session data.
+///
+/// On `insert`/`swap`/`upsert`, `V` is serialized to JSON, a fresh random
+/// 12-byte nonce is generated, and the payload is sealed with
+/// `ChaCha20-Poly1305`; `nonce || ciphertext || tag` is what the inner
+/// store actually holds. `find` splits the nonce back off, opens the
+/// seal, and deserializes `V`. Because `upsert`'s mutator needs a
+/// decrypted `&mut V` but the inner store only ever sees ciphertext, it
+/// is implemented as decrypt -> clone -> mutate -> re-encrypt -> store,
+/// rather than mutating in place.
+///
+/// Authentication failure (a corrupt record, or a value written under a
+/// since-rotated key) is treated the same as no session, not an error,
+/// so rotating the key can't wedge a client into a broken state.
+pub struct EncryptedSessionStore<S, K, V> {
+    inner: S,
+    key: [u8, ..KEY_LEN],
+    key_type: PhantomData<K>,
+    value_type: PhantomData<V>
+}
+
+impl<S: Clone, K, V> Clone for EncryptedSessionStore<S, K, V> {
+    fn clone(&self) -> EncryptedSessionStore<S, K, V> {
+        EncryptedSessionStore {
+            inner: self.inner.clone(),
+            key: self.key,
+            key_type: PhantomData,
+            value_type: PhantomData
+        }
+    }
+}
+
+impl<S: SessionStore<K, Vec<u8>>, K, V> EncryptedSessionStore<S, K, V> {
+    /// Wrap `inner` so that values are encrypted at rest with `key`, a
+    /// 32-byte `ChaCha20-Poly1305` key.
+    pub fn new(inner: S, key: [u8, ..KEY_LEN]) -> EncryptedSessionStore<S, K, V> {
+        EncryptedSessionStore {
+            inner: inner,
+            key: key,
+            key_type: PhantomData,
+            value_type: PhantomData
+        }
+    }
+}
+
+impl<S: SessionStore<K, Vec<u8>>, K, V: Encodable<json::Encoder<'static>, io::IoError>> EncryptedSessionStore<S, K, V> {
+    fn encrypt(&self, value: &V) -> Vec<u8> {
+        let plaintext = json::encode(value).into_bytes();
+        let mut nonce = [0u8, ..NONCE_LEN];
+        OsRng::new().expect("failed to open OS RNG").fill_bytes(&mut nonce);
+
+        let mut cipher = ChaCha20Poly1305::new(&self.key, &nonce, &[]);
+        let mut ciphertext = Vec::from_elem(plaintext.len(), 0u8);
+        let mut tag = [0u8, ..TAG_LEN];
+        cipher.encrypt(plaintext.as_slice(), ciphertext.as_mut_slice(), &mut tag);
+
+        let mut record = Vec::with_capacity(NONCE_LEN + ciphertext.len() + TAG_LEN);
+        record.push_all(&nonce);
+        record.push_all(ciphertext.as_slice());
+        record.push_all(&tag);
+        record
+    }
+}
+
+impl<S: SessionStore<K, Vec<u8>>, K, V: Decodable<json::Decoder, json::DecoderError>> EncryptedSessionStore<S, K, V> {
+    fn decrypt(&self, record: Vec<u8>) -> Option<V> {
+        if record.len() < NONCE_LEN + TAG_LEN {
+            return None;
+        }
+        let (nonce, rest) = record.as_slice().split_at(NONCE_LEN);
+        let (ciphertext, tag) = rest.split_at(rest.len() - TAG_LEN);
+
+        let mut cipher = ChaCha20Poly1305::new(&self.key, nonce, &[]);
+        let mut plaintext = Vec::from_elem(ciphertext.len(), 0u8);
+        if !cipher.decrypt(ciphertext, plaintext.as_mut_slice(), tag) {
+            return None;
+        }
+        match str::from_utf8(plaintext.as_slice()) {
+            Some(plaintext) => json::decode(plaintext).ok(),
+            None => None
+        }
+    }
+}
+
+impl<S, K, V> SessionStore<K, V> for EncryptedSessionStore<S, K, V>
+  where S: SessionStore<K, Vec<u8>>,
+        V: Clone + Encodable<json::Encoder<'static>, io::IoError> + Decodable<json::Decoder, json::DecoderError> {
+    fn insert(&self, key: &K, val: V) {
+        // A record that won't authenticate counts as no session, so
+        // overwrite it instead of letting the inner insert keep it.
+        match self.inner.find(key) {
+            Some(record) => if self.decrypt(record).is_none() {
+                self.inner.swap(key, self.encrypt(&val));
+            },
+            None => self.inner.insert(key, self.encrypt(&val))
+        }
+    }
+
+    fn find(&self, key: &K) -> Option<V> {
+        self.inner.find(key).and_then(|record| self.decrypt(record))
+    }
+
+    fn swap(&self, key: &K, value: V) -> Option<V> {
+        self.inner.swap(key, self.encrypt(&value)).and_then(|old| self.decrypt(old))
+    }
+
+    fn upsert<F>(&self, key: &K, value: V, mutator: F) -> V
+      where F: Fn(&mut V) -> () {
+        // The inner store only ever sees ciphertext, so it can't run the
+        // mutator itself: decrypt, clone, mutate, re-encrypt, store.
+        match self.inner.find(key) {
+            Some(record) => match self.decrypt(record) {
+                Some(mut decrypted) => {
+                    mutator(&mut decrypted);
+                    self.inner.swap(key, self.encrypt(&decrypted));
+                    decrypted
+                },
+                // A record is there but won't authenticate (corrupt, or
+                // written under a since-rotated key): treat it as absent
+                // and overwrite it, rather than leaving the stale record
+                // in place and wedging the client forever.
+                None => {
+                    self.inner.swap(key, self.encrypt(&value));
+                    value
+                }
+            },
+            None => {
+                self.inner.insert(key, self.encrypt(&value));
+                value
+            }
+        }
+    }
+
+    fn remove(&self, key: &K) -> bool {
+        self.inner.remove(key)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    pub use super::*;
+    pub use super::super::SessionStore;
+    pub use super::super::hashsession::HashSessionStore;
+
+    const KEY: [u8, ..32] = [1u8, ..32];
+    const OTHER_KEY: [u8, ..32] = [2u8, ..32];
+
+    fn store() -> EncryptedSessionStore<HashSessionStore<char, Vec<u8>>, char, String> {
+        EncryptedSessionStore::new(HashSessionStore::<char, Vec<u8>>::new(), KEY)
+    }
+
+    #[test]
+    fn round_trips_a_value_through_the_inner_store() {
+        let store = store();
+        store.insert(&'a', "hello".to_string());
+        assert_eq!(store.find(&'a'), Some("hello".to_string()));
+    }
+
+    #[test]
+    fn the_inner_store_never_sees_plaintext() {
+        let store = store();
+        store.insert(&'a', "hello".to_string());
+        let record = store.inner.find(&'a').unwrap();
+        let plaintext = json::encode(&"hello".to_string());
+        assert!(String::from_utf8_lossy(record.as_slice()).as_slice() != plaintext.as_slice());
+    }
+
+    #[test]
+    fn find_is_empty_not_an_error_for_a_record_shorter_than_nonce_plus_tag() {
+        let store = store();
+        store.inner.insert(&'a', vec![0u8; 4]);
+        assert_eq!(store.find(&'a'), None);
+    }
+
+    #[test]
+    fn find_is_empty_not_an_error_for_a_tampered_tag() {
+        let store = store();
+        store.insert(&'a', "hello".to_string());
+        let mut record = store.inner.find(&'a').unwrap();
+        let last = record.len() - 1;
+        record[last] ^= 0xff;
+        store.inner.swap(&'a', record);
+        assert_eq!(store.find(&'a'), None);
+    }
+
+    #[test]
+    fn find_is_empty_not_an_error_for_a_record_written_under_a_different_key() {
+        let writer = EncryptedSessionStore::new(HashSessionStore::<char, Vec<u8>>::new(), OTHER_KEY);
+        writer.insert(&'a', "hello".to_string());
+        let record = writer.inner.find(&'a').unwrap();
+
+        let reader = store();
+        reader.inner.insert(&'a', record);
+        assert_eq!(reader.find(&'a'), None);
+    }
+
+    #[test]
+    fn upsert_self_heals_after_authentication_failure() {
+        let store = store();
+        store.inner.insert(&'a', vec![0u8; 4]);
+        assert_eq!(store.upsert(&'a', "fresh".to_string(), |v| v.push_str("!")), "fresh".to_string());
+        // The stale, unauthenticatable record must actually be replaced,
+        // not just papered over for this one call.
+        assert_eq!(store.find(&'a'), Some("fresh".to_string()));
+    }
+}
@@ -1,14 +1,56 @@
 use std::sync::{Arc, RwLock};
 use std::collections::HashMap;
 use std::hash::Hash;
+use std::hash::sip::SipHasher;
+use std::thread::Thread;
+use std::io::timer::sleep;
+use std::os;
 use core::cmp::Eq;
+use time::{Duration, SteadyTime};
 use super::SessionStore;
 
-type Store<K, V> = RwLock<HashMap<K, RwLock<V>>>;
+/// A session value paired with the clock reading it should be considered
+/// fresh from, for TTL purposes.
+struct Entry<V> {
+    value: V,
+    fresh_since: SteadyTime
+}
+
+/// One lock-striped slice of the store. Keeping each shard behind its
+/// own `RwLock` means a write to a key in one shard never blocks a
+/// find/write to a key in another, unlike a single map-wide lock.
+type Shard<K, V> = RwLock<HashMap<K, RwLock<Entry<V>>>>;
+
+/// Controls whether a session's expiry clock resets on access.
+#[deriving(Clone)]
+pub enum Expiry {
+    /// `max_age` counts from the last time the entry was read with
+    /// `find`, mutated with `upsert`, or replaced with `swap`. Active
+    /// sessions are kept alive indefinitely.
+    Sliding,
+    /// `max_age` counts from when the value was stored with `insert` or
+    /// `swap`; `find` and `upsert` do not reset it, so a session expires
+    /// even if it is read continuously.
+    Absolute
+}
 
 /// A default implementation of `SessionStore`.
 ///
-/// Session store implemented as a read-write-locked `HashMap`.
+/// Session store implemented as an array of shards, each a
+/// read-write-locked `HashMap`, selected by hashing the key. Traffic to
+/// keys that land in different shards proceeds concurrently instead of
+/// serializing through one global lock; within a shard, the existing
+/// "avoid the write lock if the key already exists" fast path still
+/// applies.
+///
+/// Entries may optionally expire after `max_age`, set via `with_ttl`.
+/// Expiry is checked lazily on every `insert`/`find`/`swap`/`upsert`, so
+/// an expired entry is treated as absent the moment it is looked up
+/// (`remove` drops an expired entry like any other and reports it as
+/// removed); a
+/// background reaper started with `start_reaper` additionally sweeps
+/// every shard on an interval so sessions that are never looked up again
+/// are still collected.
 ///
 /// #### To use:
 /// ```ignore
@@ -19,23 +61,120 @@ type Store<K, V> = RwLock<HashMap<K, RwLock<V>>>;
 /// let session = alloy.find_mut::<Session<KeyType, ValueType>>().unwrap();
 /// ```
 pub struct HashSessionStore<K, V>{
-    store: Arc<Store<K, V>>
+    shards: Arc<Vec<Shard<K, V>>>,
+    max_age: Option<Duration>,
+    expiry: Expiry
 }
 
 impl<K: Clone + Send, V: Send> Clone for HashSessionStore<K, V> {
     fn clone(&self) -> HashSessionStore<K, V> {
         HashSessionStore {
-            store: self.store.clone()
+            shards: self.shards.clone(),
+            max_age: self.max_age,
+            expiry: self.expiry.clone()
         }
     }
 }
 
+/// Default shard count: a power of two near the number of CPUs, so
+/// concurrent writers rarely contend on the same shard.
+fn default_shard_count() -> uint {
+    os::num_cpus().next_power_of_two()
+}
+
+fn new_shards<K: Send + Sync, V: Send + Sync>(n: uint) -> Vec<Shard<K, V>> {
+    range(0, n).map(|_| RwLock::new(HashMap::<K, RwLock<Entry<V>>>::new())).collect()
+}
+
 impl<K: Hash + Eq + Send + Sync, V: Send + Sync> HashSessionStore<K, V> {
-    /// Create a new instance of the session store
+    /// Create a new instance of the session store. Entries never expire.
+    /// Uses a default number of shards near the CPU count.
     pub fn new() -> HashSessionStore<K, V> {
+        HashSessionStore::with_shards(default_shard_count())
+    }
+
+    /// Create a new instance of the session store backed by `n` shards.
+    /// `n` should be a power of two so key hashes distribute evenly.
+    pub fn with_shards(n: uint) -> HashSessionStore<K, V> {
         HashSessionStore {
-            store: Arc::new(RwLock::new(HashMap::<K, RwLock<V>>::new()))
+            shards: Arc::new(new_shards(n)),
+            max_age: None,
+            expiry: Expiry::Sliding
+        }
+    }
+
+    /// Create a new instance of the session store whose entries expire
+    /// after `max_age`. Uses `Sliding` expiry, meaning `find` refreshes
+    /// an entry's clock; call `.with_expiry(Expiry::Absolute)` to count
+    /// from creation time instead. Uses a default number of shards.
+    pub fn with_ttl(max_age: Duration) -> HashSessionStore<K, V> {
+        HashSessionStore {
+            shards: Arc::new(new_shards(default_shard_count())),
+            max_age: Some(max_age),
+            expiry: Expiry::Sliding
+        }
+    }
+
+    /// Override whether the TTL set by `with_ttl` is sliding or absolute.
+    pub fn with_expiry(mut self, expiry: Expiry) -> HashSessionStore<K, V> {
+        self.expiry = expiry;
+        self
+    }
+
+    fn shard(&self, key: &K) -> &Shard<K, V> {
+        let mut hasher = SipHasher::new();
+        key.hash(&mut hasher);
+        let index = (hasher.finish() as uint) % self.shards.len();
+        &self.shards[index]
+    }
+
+    fn is_expired(&self, entry: &Entry<V>) -> bool {
+        match self.max_age {
+            Some(max_age) => SteadyTime::now() - entry.fresh_since > max_age,
+            None => false
+        }
+    }
+
+    /// Number of entries currently held across all shards, including any
+    /// that have expired but have not yet been looked up or reaped.
+    pub fn len(&self) -> uint {
+        self.shards.iter().map(|shard| shard.read().len()).fold(0, |a, b| a + b)
+    }
+
+    /// Drop every entry that has expired, in every shard. Returns the
+    /// number removed.
+    ///
+    /// Useful in tests to assert eviction without waiting for a lookup
+    /// or the background reaper to run.
+    pub fn clear_expired(&self) -> uint {
+        let max_age = self.max_age;
+        self.shards.iter().map(|shard| {
+            let mut shard = shard.write();
+            let before = shard.len();
+            shard.retain(|_, lock| {
+                match max_age {
+                    Some(max_age) => SteadyTime::now() - lock.read().fresh_since <= max_age,
+                    None => true
+                }
+            });
+            before - shard.len()
+        }).fold(0, |a, b| a + b)
+    }
+
+    /// Spawn a background thread that calls `clear_expired` every
+    /// `interval`, so idle sessions are collected even if no one ever
+    /// looks them up again. Has no effect if no `max_age` was set.
+    pub fn start_reaper(&self, interval: Duration) where K: 'static, V: 'static {
+        if self.max_age.is_none() {
+            return;
         }
+        let store = self.clone();
+        Thread::spawn(move || {
+            loop {
+                sleep(interval);
+                store.clear_expired();
+            }
+        }).detach();
     }
 }
 
@@ -49,26 +188,48 @@ impl<K: Hash + Eq + Send + Sync, V: Send + Sync> HashSessionStore<K, V> {
  */
 impl<K: Hash + Eq + Send + Sync + Clone, V: Send + Sync + Clone> SessionStore<K, V> for HashSessionStore<K, V> {
     fn insert(&self, key: &K, val: V) {
-        // Avoid a WriteLock if possible
-        if !self.store.read().contains_key(key) {
+        // Avoid a WriteLock if possible. An expired entry is treated the
+        // same as an absent one -- otherwise a key whose previous session
+        // expired but hasn't yet been looked up would silently swallow
+        // the new value and keep the stale one live indefinitely.
+        let present = match self.shard(key).read().find(key) {
+            Some(lock) => !self.is_expired(&*lock.read()),
+            None => false
+        };
+        if !present {
             // Inserting consumes a key => clone()
-            self.store.write().insert(key.clone(), RwLock::new(val));
+            let entry = Entry { value: val, fresh_since: SteadyTime::now() };
+            self.shard(key).write().insert(key.clone(), RwLock::new(entry));
         }
     }
     fn find(&self, key: &K) -> Option<V> {
-        match self.store.read().find(key) {
-            Some(lock) => Some(lock.read().clone()),
-            None => None
+        match self.shard(key).read().find(key) {
+            Some(lock) => {
+                if !self.is_expired(&*lock.read()) {
+                    if let Expiry::Sliding = self.expiry {
+                        lock.write().fresh_since = SteadyTime::now();
+                    }
+                    return Some(lock.read().value.clone())
+                }
+            },
+            None => return None
         }
+        // Only reached when the entry existed but had expired: the read
+        // lock above is released by now, so drop the stale entry under a
+        // write lock, the same way swap/upsert already self-heal, instead
+        // of leaving it to grow the map forever.
+        self.shard(key).write().remove(key);
+        None
     }
     fn swap(&self, key: &K, value: V) -> Option<V> {
-        match self.store.read().find(key) {
-            // Instead of using swap, which requires a write lock on the HashMap,
+        match self.shard(key).read().find(key) {
+            // Instead of using swap, which requires a write lock on the shard's map,
             // only take the write locks when the key does not yet exist
             Some(lock) => {
-                let old_v = lock.read().clone();
-                *lock.write() = value;
-                return Some(old_v)
+                let expired = self.is_expired(&*lock.read());
+                let old_v = if expired { None } else { Some(lock.read().value.clone()) };
+                *lock.write() = Entry { value: value, fresh_since: SteadyTime::now() };
+                return old_v
             },
             None => ()
         }
@@ -77,11 +238,18 @@ impl<K: Hash + Eq + Send + Sync + Clone, V: Send + Sync + Clone> SessionStore<K,
     }
     fn upsert<F>(&self, key: &K, value: V, mutator: F) -> V
       where F: Fn(&mut V) -> () {
-        match self.store.read().find(key) {
+        match self.shard(key).read().find(key) {
             Some(lock) => {
-                let old_v = &mut *lock.write();
-                mutator(old_v);
-                return old_v.clone()
+                let mut entry = lock.write();
+                if self.is_expired(&*entry) {
+                    *entry = Entry { value: value.clone(), fresh_since: SteadyTime::now() };
+                    return value
+                }
+                mutator(&mut entry.value);
+                if let Expiry::Sliding = self.expiry {
+                    entry.fresh_since = SteadyTime::now();
+                }
+                return entry.value.clone()
             },
             None => ()
         }
@@ -89,7 +257,7 @@ impl<K: Hash + Eq + Send + Sync + Clone, V: Send + Sync + Clone> SessionStore<K,
         value
     }
     fn remove(&self, key: &K) -> bool {
-        self.store.write().remove(key)
+        self.shard(key).write().remove(key)
     }
 }
 
@@ -101,6 +269,7 @@ mod test {
     pub use super::super::super::sessions::*;
     pub use iron::*;
     pub use test::mock::{request, response};
+    pub use time::Duration;
 
     pub fn set_server() -> Server {
         let mut test_server: Server = Iron::new();
@@ -113,7 +282,7 @@ mod test {
             &mut response::new());
     }
 
-    pub fn get_session_id(_: &Request) -> char {'a'}
+    pub fn get_session_id(_: &Request) -> IronResult<Option<char>> { Ok(Some('a')) }
 
     pub fn set_session_to_a(req: &mut Request, _: &mut Response) -> Status {
         let session = req.alloy.find::<Session<char, char>>().unwrap();
@@ -236,4 +405,133 @@ mod test {
             run_server(test_server);
         }
     }
+
+    mod ttl {
+        use super::*;
+        use std::io::timer::sleep;
+
+        #[test]
+        fn expires_entries_lazily() {
+            let store = HashSessionStore::<char, char>::with_ttl(Duration::milliseconds(10));
+            store.insert(&'a', 'x');
+            assert_eq!(store.find(&'a'), Some('x'));
+            sleep(Duration::milliseconds(50));
+            assert_eq!(store.find(&'a'), None);
+        }
+
+        #[test]
+        fn find_drops_an_expired_entry_instead_of_just_hiding_it() {
+            let store = HashSessionStore::<char, char>::with_ttl(Duration::milliseconds(10));
+            store.insert(&'a', 'x');
+            sleep(Duration::milliseconds(50));
+            assert_eq!(store.len(), 1);
+            assert_eq!(store.find(&'a'), None);
+            assert_eq!(store.len(), 0);
+        }
+
+        #[test]
+        fn clear_expired_reaps_without_a_lookup() {
+            let store = HashSessionStore::<char, char>::with_ttl(Duration::milliseconds(10));
+            store.insert(&'a', 'x');
+            store.insert(&'b', 'y');
+            sleep(Duration::milliseconds(50));
+            assert_eq!(store.clear_expired(), 2);
+            assert_eq!(store.len(), 0);
+        }
+
+        #[test]
+        fn sliding_expiry_refreshes_on_find() {
+            let store = HashSessionStore::<char, char>::with_ttl(Duration::milliseconds(30));
+            store.insert(&'a', 'x');
+            sleep(Duration::milliseconds(20));
+            assert_eq!(store.find(&'a'), Some('x'));
+            sleep(Duration::milliseconds(20));
+            assert_eq!(store.find(&'a'), Some('x'));
+        }
+
+        #[test]
+        fn absolute_expiry_ignores_access() {
+            let store = HashSessionStore::<char, char>::with_ttl(Duration::milliseconds(30))
+                .with_expiry(Expiry::Absolute);
+            store.insert(&'a', 'x');
+            sleep(Duration::milliseconds(20));
+            assert_eq!(store.find(&'a'), Some('x'));
+            sleep(Duration::milliseconds(20));
+            assert_eq!(store.find(&'a'), None);
+        }
+
+        #[test]
+        fn insert_replaces_an_expired_entry_instead_of_no_oping() {
+            let store = HashSessionStore::<char, char>::with_ttl(Duration::milliseconds(10));
+            store.insert(&'a', 'x');
+            sleep(Duration::milliseconds(50));
+            store.insert(&'a', 'y');
+            assert_eq!(store.find(&'a'), Some('y'));
+        }
+
+        #[test]
+        fn absolute_expiry_ignores_upsert() {
+            let store = HashSessionStore::<char, char>::with_ttl(Duration::milliseconds(30))
+                .with_expiry(Expiry::Absolute);
+            store.insert(&'a', 'x');
+            sleep(Duration::milliseconds(20));
+            // Mutating with upsert must not reset the absolute expiry clock,
+            // the way find/upsert under Sliding expiry would.
+            assert_eq!(store.upsert(&'a', 'z', |v| *v = 'y'), 'y');
+            sleep(Duration::milliseconds(20));
+            assert_eq!(store.find(&'a'), None);
+        }
+    }
+
+    mod sharding {
+        use super::*;
+
+        #[test]
+        fn concurrent_inserts_across_shards_are_not_lost() {
+            let store = HashSessionStore::<uint, uint>::with_shards(8);
+            let guards: Vec<_> = range(0u, 200).map(|i| {
+                let store = store.clone();
+                Thread::spawn(move || {
+                    store.insert(&i, i);
+                    let _ = store.upsert(&i, i, |v| *v += 1);
+                })
+            }).collect();
+            for guard in guards {
+                guard.join().ok().expect("writer thread panicked");
+            }
+            assert_eq!(store.len(), 200);
+            for i in range(0u, 200) {
+                assert_eq!(store.find(&i), Some(i + 1));
+            }
+        }
+
+        #[test]
+        fn concurrent_upserts_on_the_same_key_lose_no_updates() {
+            // A small key set forces many threads to land on the same
+            // shard, and often the same entry, exercising the per-entry
+            // lock that upsert relies on to stay atomic under contention.
+            let store = HashSessionStore::<uint, uint>::with_shards(4);
+            let keys = [0u, 1, 2];
+            for &key in keys.iter() {
+                store.insert(&key, 0);
+            }
+
+            let writers_per_key = 50u;
+            let guards: Vec<_> = keys.iter().flat_map(|&key| {
+                range(0, writers_per_key).map(move |_| {
+                    let store = store.clone();
+                    Thread::spawn(move || {
+                        let _ = store.upsert(&key, 1, |v| *v += 1);
+                    })
+                })
+            }).collect();
+            for guard in guards {
+                guard.join().ok().expect("writer thread panicked");
+            }
+
+            for &key in keys.iter() {
+                assert_eq!(store.find(&key), Some(writers_per_key));
+            }
+        }
+    }
 }